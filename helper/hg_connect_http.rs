@@ -13,6 +13,7 @@ use std::ptr;
 use std::str::FromStr;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
 
 use bstr::ByteSlice;
 use byteorder::ReadBytesExt;
@@ -20,10 +21,15 @@ use bzip2::read::BzDecoder;
 use cstr::cstr;
 use curl_sys::{
     curl_easy_getinfo, curl_easy_setopt, curl_slist_append, curl_slist_free_all, CURL,
-    CURLINFO_CONTENT_TYPE, CURLINFO_EFFECTIVE_URL, CURLINFO_REDIRECT_COUNT, CURLINFO_RESPONSE_CODE,
-    CURLOPT_FAILONERROR, CURLOPT_FILE, CURLOPT_FOLLOWLOCATION, CURLOPT_HTTPGET, CURLOPT_HTTPHEADER,
-    CURLOPT_NOBODY, CURLOPT_POST, CURLOPT_POSTFIELDSIZE_LARGE, CURLOPT_READDATA,
-    CURLOPT_READFUNCTION, CURLOPT_URL, CURLOPT_USERAGENT, CURLOPT_WRITEFUNCTION,
+    CURLAUTH_ANY, CURLAUTH_BASIC, CURLAUTH_DIGEST, CURLAUTH_NEGOTIATE, CURLAUTH_NTLM,
+    CURLE_COULDNT_CONNECT, CURLE_GOT_NOTHING, CURLE_OPERATION_TIMEDOUT, CURLE_PARTIAL_FILE,
+    CURLE_RECV_ERROR, CURLE_SEND_ERROR, CURLINFO_CONTENT_TYPE, CURLINFO_EFFECTIVE_URL,
+    CURLINFO_REDIRECT_COUNT, CURLINFO_RESPONSE_CODE, CURLOPT_FAILONERROR, CURLOPT_FILE,
+    CURLOPT_FOLLOWLOCATION, CURLOPT_HTTPGET, CURLOPT_HTTPHEADER, CURLOPT_HTTP_VERSION,
+    CURLOPT_NOBODY, CURLOPT_POST, CURLOPT_POSTFIELDSIZE_LARGE, CURLOPT_PROXYAUTH, CURLOPT_READDATA,
+    CURLOPT_READFUNCTION, CURLOPT_SSLVERSION, CURLOPT_URL, CURLOPT_USERAGENT, CURLOPT_WRITEFUNCTION,
+    CURL_HTTP_VERSION_1_1, CURL_HTTP_VERSION_2_0, CURL_SSLVERSION_TLSv1_0, CURL_SSLVERSION_TLSv1_1,
+    CURL_SSLVERSION_TLSv1_2, CURL_SSLVERSION_TLSv1_3,
 };
 use either::Either;
 use flate2::read::ZlibDecoder;
@@ -31,6 +37,7 @@ use url::{form_urlencoded, Url};
 use zstd::stream::read::Decoder as ZstdDecoder;
 
 use crate::args;
+use crate::get_config;
 use crate::hg_bundle::DecompressBundleReader;
 use crate::hg_connect::{
     HgArgs, HgCapabilities, HgConnection, HgConnectionBase, HgWireConnection, OneHgArg,
@@ -63,13 +70,75 @@ impl<T: Read + Seek> ReadAndSeek for T {}
 
 struct HttpClient {
     initial_request: bool,
+    http_version: Option<c_long>,
+    ssl_version: Option<c_long>,
+    proxy_auth: Option<c_long>,
+}
+
+/// `cinnabar.http-version` mapped to a `CURLOPT_HTTP_VERSION` value.
+fn config_http_version() -> Option<c_long> {
+    let value = get_config("http-version")?;
+    match value.to_str()?.trim() {
+        "1.1" => Some(CURL_HTTP_VERSION_1_1 as c_long),
+        "2" | "2.0" => Some(CURL_HTTP_VERSION_2_0 as c_long),
+        other => {
+            eprintln!("warning: unknown cinnabar.http-version '{}'; ignoring", other);
+            None
+        }
+    }
+}
+
+/// `http.sslVersion`-style floor mapped to a `CURLOPT_SSLVERSION` value.
+fn config_ssl_version() -> Option<c_long> {
+    let value = get_config("ssl-version")?;
+    match value.to_str()?.trim().to_ascii_lowercase().as_str() {
+        "tlsv1.0" | "tls1.0" => Some(CURL_SSLVERSION_TLSv1_0 as c_long),
+        "tlsv1.1" | "tls1.1" => Some(CURL_SSLVERSION_TLSv1_1 as c_long),
+        "tlsv1.2" | "tls1.2" => Some(CURL_SSLVERSION_TLSv1_2 as c_long),
+        "tlsv1.3" | "tls1.3" => Some(CURL_SSLVERSION_TLSv1_3 as c_long),
+        other => {
+            eprintln!("warning: unknown cinnabar.ssl-version '{}'; ignoring", other);
+            None
+        }
+    }
+}
+
+/// Proxy authentication method mapped to a `CURLOPT_PROXYAUTH` bitmask.
+fn config_proxy_auth() -> Option<c_long> {
+    let value = get_config("proxy-auth-method")?;
+    match value.to_str()?.trim().to_ascii_lowercase().as_str() {
+        "basic" => Some(CURLAUTH_BASIC as c_long),
+        "digest" => Some(CURLAUTH_DIGEST as c_long),
+        "negotiate" => Some(CURLAUTH_NEGOTIATE as c_long),
+        "ntlm" => Some(CURLAUTH_NTLM as c_long),
+        "anyauth" | "any" => Some(CURLAUTH_ANY as c_long),
+        other => {
+            eprintln!(
+                "warning: unknown cinnabar.proxy-auth-method '{}'; ignoring",
+                other
+            );
+            None
+        }
+    }
+}
+
+/// The body of a POST request. A seekable body is sent with an up-front
+/// content length and can be replayed (e.g. on a 401); a streaming body is
+/// sent with chunked transfer-encoding and is consumed as it goes, so it
+/// can't be replayed.
+enum PostBody {
+    Seekable(Box<dyn ReadAndSeek + Send>),
+    Streaming(Box<dyn Read + Send>),
 }
 
 struct HttpRequest {
     url: Url,
     headers: Vec<(String, String)>,
-    body: Option<Box<dyn ReadAndSeek + Send>>,
+    body: Option<PostBody>,
     follow_redirects: bool,
+    http_version: Option<c_long>,
+    ssl_version: Option<c_long>,
+    proxy_auth: Option<c_long>,
 }
 
 #[derive(Debug)]
@@ -82,7 +151,7 @@ struct HttpResponseInfo {
 #[derive(Debug)]
 struct HttpResponse {
     info: HttpResponseInfo,
-    thread: Option<JoinHandle<Result<(), (c_int, HttpRequest)>>>,
+    thread: Option<JoinHandle<Result<(), (HttpError, HttpRequest)>>>,
     cursor: Cursor<Vec<u8>>,
     receiver: Option<Receiver<HttpRequestChannelData>>,
 }
@@ -95,15 +164,81 @@ struct HttpThreadData {
     first: bool,
 }
 
+/// A failed request, carrying enough context to decide whether to retry it.
+#[derive(Clone, Copy)]
+struct HttpError {
+    /// The `HTTP_*` result returned by `run_one_slot`.
+    result: c_int,
+    /// The underlying `CURLcode`.
+    curl_result: c_int,
+    /// The HTTP status, or 0 when the request never got a response.
+    http_status: usize,
+}
+
+impl HttpError {
+    /// Whether the failure looks transient, i.e. worth retrying rather than
+    /// aborting. This mirrors the set of curl result codes and HTTP statuses
+    /// that typically indicate a hiccup on the wire rather than a permanent
+    /// condition.
+    fn is_spurious(&self) -> bool {
+        let curl_result = self.curl_result as u32;
+        if curl_result == CURLE_COULDNT_CONNECT as u32
+            || curl_result == CURLE_OPERATION_TIMEDOUT as u32
+            || curl_result == CURLE_RECV_ERROR as u32
+            || curl_result == CURLE_SEND_ERROR as u32
+            || curl_result == CURLE_GOT_NOTHING as u32
+            || curl_result == CURLE_PARTIAL_FILE as u32
+        {
+            return true;
+        }
+        self.http_status == 429 || (500..600).contains(&self.http_status)
+    }
+}
+
+/// Number of times a spurious failure is retried, from `cinnabar.http-retries`.
+fn http_retries() -> usize {
+    get_config("http-retries")
+        .and_then(|v| usize::from_str(v.to_str()?).ok())
+        .unwrap_or(3)
+}
+
+/// Whether `push_command` streams the bundle with chunked transfer-encoding
+/// instead of sending it as a single sized body, from
+/// `cinnabar.http-push-chunked`. Off by default, because a seekable body lets
+/// `execute` replay the upload on reauth or retry and lets the server see a
+/// Content-Length;
+/// turning it on trades that away to avoid seeking a very large bundle.
+fn http_push_chunked() -> bool {
+    get_config("http-push-chunked")
+        .and_then(|v| Some(matches!(v.to_str().ok()?.trim(), "1" | "true" | "yes" | "on")))
+        .unwrap_or(false)
+}
+
+/// Exponential backoff (1s, 2s, 4s, ...) with up to a second of jitter, so a
+/// fleet of clients hitting a struggling server doesn't retry in lockstep.
+fn retry_backoff(attempt: usize) -> Duration {
+    let base = Duration::from_secs(1 << attempt.min(6));
+    let jitter = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| u64::from(d.subsec_nanos()) % 1000);
+    base + Duration::from_millis(jitter)
+}
+
 impl HttpClient {
     fn new() -> Self {
         HttpClient {
             initial_request: true,
+            http_version: config_http_version(),
+            ssl_version: config_ssl_version(),
+            proxy_auth: config_proxy_auth(),
         }
     }
 
     fn request(&mut self, url: Url) -> HttpRequest {
         let mut req = HttpRequest::new(url);
+        req.http_version = self.http_version;
+        req.ssl_version = self.ssl_version;
+        req.proxy_auth = self.proxy_auth;
         let follow_config = unsafe { http_follow_config };
         if (follow_config == http_follow_config::HTTP_FOLLOW_INITIAL && self.initial_request)
             || follow_config == http_follow_config::HTTP_FOLLOW_ALWAYS
@@ -124,6 +259,9 @@ impl HttpRequest {
             headers: Vec::new(),
             body: None,
             follow_redirects: false,
+            http_version: None,
+            ssl_version: None,
+            proxy_auth: None,
         }
     }
 
@@ -136,10 +274,48 @@ impl HttpRequest {
     }
 
     fn post_data(&mut self, data: Box<dyn ReadAndSeek + Send>) {
-        self.body = Some(data);
+        self.body = Some(PostBody::Seekable(data));
+    }
+
+    /// Set a streaming POST body, sent with chunked transfer-encoding. Unlike
+    /// [`post_data`](Self::post_data) this doesn't require a known length or a
+    /// seekable source, but the body can't be replayed, so `execute` sends an
+    /// auth probe first and doesn't retry the request itself.
+    fn post_data_stream(&mut self, data: Box<dyn Read + Send>) {
+        self.body = Some(PostBody::Streaming(data));
     }
 
-    fn execute_once(mut self) -> Result<HttpResponse, (c_int, Self)> {
+    /// Whether the body (if any) can be replayed for a retry or reauth.
+    fn body_is_replayable(&self) -> bool {
+        !matches!(self.body, Some(PostBody::Streaming(_)))
+    }
+
+    /// Send a zero-length, bodyless request to the same URL so that any 401 is
+    /// handled up front. This is used before a non-replayable (streaming) body
+    /// is consumed; failures other than reauth are left for the real request to
+    /// report.
+    fn auth_probe(&self) {
+        let mut probe = HttpRequest::new(self.url.clone());
+        probe.http_version = self.http_version;
+        probe.ssl_version = self.ssl_version;
+        probe.proxy_auth = self.proxy_auth;
+        for (name, value) in &self.headers {
+            probe.header(name, value);
+        }
+        let mut reauthed = false;
+        loop {
+            match probe.execute_once() {
+                Err((error, this)) if error.result == HTTP_REAUTH && !reauthed => {
+                    reauthed = true;
+                    unsafe { credential_fill(&mut http_auth) };
+                    probe = this;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn execute_once(mut self) -> Result<HttpResponse, (HttpError, Self)> {
         let (sender, receiver) = channel::<HttpRequestChannelData>();
         let thread = thread::spawn(move || unsafe {
             let url = CString::new(self.url.to_string()).unwrap();
@@ -155,6 +331,15 @@ impl HttpRequest {
                 CURLOPT_USERAGENT,
                 cstr!("mercurial/proto-1.0").as_ptr(),
             );
+            if let Some(http_version) = self.http_version {
+                curl_easy_setopt(slot.curl, CURLOPT_HTTP_VERSION, http_version);
+            }
+            if let Some(ssl_version) = self.ssl_version {
+                curl_easy_setopt(slot.curl, CURLOPT_SSLVERSION, ssl_version);
+            }
+            if let Some(proxy_auth) = self.proxy_auth {
+                curl_easy_setopt(slot.curl, CURLOPT_PROXYAUTH, proxy_auth);
+            }
             let mut data = HttpThreadData {
                 sender,
                 curl: slot.curl,
@@ -167,29 +352,49 @@ impl HttpRequest {
                 http_request_execute as *const c_void,
             );
             let mut headers = ptr::null_mut();
-            if let Some(ref mut body) = self.body {
-                curl_easy_setopt(slot.curl, CURLOPT_POST, 1);
-                curl_easy_setopt(
-                    slot.curl,
-                    CURLOPT_POSTFIELDSIZE_LARGE,
-                    body.stream_len_().unwrap(),
-                );
-                /* Ensure we have no state from a previous attempt that failed because
-                 * of authentication (401). */
-                body.seek(SeekFrom::Start(0)).unwrap();
-                curl_easy_setopt(slot.curl, CURLOPT_READDATA, &mut *body);
-                curl_easy_setopt(
-                    slot.curl,
-                    CURLOPT_READFUNCTION,
-                    read_from_read::<&mut (dyn ReadAndSeek + Send)> as *const c_void,
-                );
-                curl_easy_setopt(slot.curl, CURLOPT_FOLLOWLOCATION, 0);
-                headers = curl_slist_append(headers, cstr!("Expect:").as_ptr());
-            } else {
-                if self.follow_redirects {
-                    curl_easy_setopt(slot.curl, CURLOPT_FOLLOWLOCATION, 1);
+            match self.body {
+                Some(PostBody::Seekable(ref mut body)) => {
+                    curl_easy_setopt(slot.curl, CURLOPT_POST, 1);
+                    curl_easy_setopt(
+                        slot.curl,
+                        CURLOPT_POSTFIELDSIZE_LARGE,
+                        body.stream_len_().unwrap(),
+                    );
+                    /* Ensure we have no state from a previous attempt that failed because
+                     * of authentication (401). */
+                    body.seek(SeekFrom::Start(0)).unwrap();
+                    curl_easy_setopt(slot.curl, CURLOPT_READDATA, &mut *body);
+                    curl_easy_setopt(
+                        slot.curl,
+                        CURLOPT_READFUNCTION,
+                        read_from_read::<&mut (dyn ReadAndSeek + Send)> as *const c_void,
+                    );
+                    curl_easy_setopt(slot.curl, CURLOPT_FOLLOWLOCATION, 0);
+                    headers = curl_slist_append(headers, cstr!("Expect:").as_ptr());
+                }
+                Some(PostBody::Streaming(ref mut body)) => {
+                    /* No POSTFIELDSIZE: the length is unknown up front, so curl
+                     * sends the body with chunked transfer-encoding. Such a body
+                     * can't be replayed, which is why the reauth probe runs
+                     * before we get here. */
+                    curl_easy_setopt(slot.curl, CURLOPT_POST, 1);
+                    curl_easy_setopt(slot.curl, CURLOPT_READDATA, &mut *body);
+                    curl_easy_setopt(
+                        slot.curl,
+                        CURLOPT_READFUNCTION,
+                        read_from_read::<&mut (dyn Read + Send)> as *const c_void,
+                    );
+                    curl_easy_setopt(slot.curl, CURLOPT_FOLLOWLOCATION, 0);
+                    headers = curl_slist_append(headers, cstr!("Expect:").as_ptr());
+                    headers =
+                        curl_slist_append(headers, cstr!("Transfer-Encoding: chunked").as_ptr());
+                }
+                None => {
+                    if self.follow_redirects {
+                        curl_easy_setopt(slot.curl, CURLOPT_FOLLOWLOCATION, 1);
+                    }
+                    curl_easy_setopt(slot.curl, CURLOPT_HTTPGET, 1);
                 }
-                curl_easy_setopt(slot.curl, CURLOPT_HTTPGET, 1);
             }
             for (name, value) in self.headers.iter() {
                 let header_line = CString::new(format!("{}: {}", name, value)).unwrap();
@@ -199,11 +404,19 @@ impl HttpRequest {
             let mut results = slot_results::new();
             let result = run_one_slot(slot, &mut results);
             curl_slist_free_all(headers);
-            http_send_info(&mut data);
-            if result == HTTP_OK {
+            let http_status = results.http_code as usize;
+            if result == HTTP_OK && http_status < 300 {
+                http_send_info(&mut data);
                 Ok(())
             } else {
-                Err((result, self))
+                Err((
+                    HttpError {
+                        result,
+                        curl_result: results.curl_result as c_int,
+                        http_status,
+                    },
+                    self,
+                ))
             }
         });
 
@@ -215,33 +428,66 @@ impl HttpRequest {
                 receiver: Some(receiver),
             }),
             Ok(Either::Right(_)) => unreachable!(),
+            // Either the server answered with an error status (the worker sent
+            // the response info while streaming the error body), or the request
+            // failed before producing any response at all. In both cases the
+            // worker's `Err` arm gives back `self` together with the classified
+            // error, so drain any buffered body and surface that.
             _ => {
                 while receiver.recv().is_ok() {}
                 drop(receiver);
-                thread.join().unwrap()?;
-                unreachable!();
+                Err(thread.join().unwrap().unwrap_err())
             }
         }
     }
 
     fn execute(self) -> Result<HttpResponse, ()> {
-        self.execute_once()
-            .or_else(|(result, this)| {
-                if result == HTTP_REAUTH {
-                    unsafe { credential_fill(&mut http_auth) };
-                    this.execute_once()
-                } else {
-                    Err((result, this))
+        let max_retries = http_retries();
+        let mut request = self;
+        // A streaming body can't be replayed, so establish credentials up front
+        // and don't retry the request itself below.
+        let replayable = request.body_is_replayable();
+        if !replayable {
+            request.auth_probe();
+        }
+        // Reauthentication is handled out of band and doesn't count against the
+        // retry budget; it is only ever attempted once per request.
+        let mut reauthed = false;
+        let mut retries = 0;
+        loop {
+            match request.execute_once() {
+                Ok(response) => return Ok(response),
+                Err((error, mut this)) => {
+                    if replayable && error.result == HTTP_REAUTH && !reauthed {
+                        reauthed = true;
+                        unsafe { credential_fill(&mut http_auth) };
+                        request = this;
+                        continue;
+                    }
+                    if replayable && error.is_spurious() && retries < max_retries {
+                        thread::sleep(retry_backoff(retries));
+                        retries += 1;
+                        request = this;
+                        continue;
+                    }
+                    this.url.set_query(None);
+                    let curl_error =
+                        unsafe { CStr::from_ptr(curl_errorstr.as_ptr()).to_bytes() };
+                    // For HTTP-status failures the curl transport itself
+                    // succeeded, so `curl_errorstr` is empty; fall back to the
+                    // status code in that case.
+                    if curl_error.is_empty() && error.http_status != 0 {
+                        die!(
+                            "unable to access '{}': HTTP Error {}",
+                            this.url,
+                            error.http_status
+                        );
+                    } else {
+                        die!("unable to access '{}': {}", this.url, curl_error.as_bstr());
+                    }
                 }
-            })
-            .map_err(|(_, mut this)| unsafe {
-                this.url.set_query(None);
-                die!(
-                    "unable to access '{}': {}",
-                    this.url,
-                    CStr::from_ptr(curl_errorstr.as_ptr()).to_bytes().as_bstr()
-                );
-            })
+            }
+        }
     }
 }
 
@@ -400,6 +646,95 @@ impl HgHttpConnection {
         request
     }
 
+    /// Negotiate the ordered list of compression engines to advertise in the
+    /// `comp=` portion of `X-HgProto-1`. The result is the intersection of the
+    /// engines we can actually decode, what the server says it supports (via
+    /// the `compression` capability and the `comp=` part of `httpmediatype`),
+    /// and the user's `cinnabar.compression` preference, kept in preference
+    /// order. Two exceptions: `none` is always included, and when the server
+    /// advertises no engine at all, no server-side filtering is applied.
+    fn negotiate_compression(&self) -> Vec<&'static str> {
+        // Engines compiled into this build, in Mercurial's default order.
+        const ENGINES: [&str; 4] = ["zstd", "zlib", "none", "bzip2"];
+
+        // Engines the server advertises. The `compression` capability is a bare
+        // comma-separated list of engine names; `httpmediatype` instead carries
+        // them in a `comp=` parameter alongside unrelated media-type tokens.
+        let mut advertised: Vec<&str> = Vec::new();
+        let mut advertise = |engine: &str| {
+            let engine = engine.trim();
+            if let Some(engine) = ENGINES.into_iter().find(|e| *e == engine) {
+                if !advertised.contains(&engine) {
+                    advertised.push(engine);
+                }
+            }
+        };
+        if let Some(value) = self
+            .get_capability(b"compression")
+            .and_then(|c| c.to_str().ok())
+        {
+            value.split(',').for_each(&mut advertise);
+        }
+        if let Some(value) = self
+            .get_capability(b"httpmediatype")
+            .and_then(|c| c.to_str().ok())
+        {
+            if let Some(idx) = value.find("comp=") {
+                let rest = &value[idx + "comp=".len()..];
+                rest.split(';')
+                    .next()
+                    .unwrap_or(rest)
+                    .split(',')
+                    .for_each(&mut advertise);
+            }
+        }
+
+        // The user preference both filters and reorders; absent it, the default
+        // order above applies.
+        let ordered: Vec<&'static str> = match get_config("compression") {
+            Some(pref) => {
+                let selected: Vec<&'static str> = pref
+                    .to_str()
+                    .unwrap_or("")
+                    .split(',')
+                    .filter_map(|p| ENGINES.into_iter().find(|e| *e == p.trim()))
+                    .collect();
+                if selected.is_empty() {
+                    eprintln!(
+                        "warning: cinnabar.compression names no supported engine; ignoring"
+                    );
+                    ENGINES.to_vec()
+                } else {
+                    selected
+                }
+            }
+            None => ENGINES.to_vec(),
+        };
+
+        // `none` is not really a negotiable engine: we can always accept an
+        // uncompressed response, and Mercurial servers routinely reply with
+        // `comp=none` for small payloads even when their `compression`
+        // capability only lists the real engines. Keep it regardless of what
+        // the user asked for or what was advertised, so we never reject a
+        // valid uncompressed response. The user preference above can drop it
+        // (e.g. `cinnabar.compression=zstd`), so re-add it here rather than
+        // relying on it surviving the advertised intersection below.
+        let mut ordered = ordered;
+        if !ordered.contains(&"none") {
+            ordered.push("none");
+        }
+        let negotiated: Vec<&'static str> = ordered
+            .into_iter()
+            .filter(|e| *e == "none" || advertised.is_empty() || advertised.contains(e))
+            .collect();
+        if negotiated.is_empty() {
+            // Don't end up advertising nothing; `none` is always safe.
+            vec!["none"]
+        } else {
+            negotiated
+        }
+    }
+
     fn handle_redirect(&mut self, http_resp: &HttpResponse) {
         if let Some(url) = http_resp.redirected_to() {
             let mut new_url = url.clone();
@@ -408,6 +743,7 @@ impl HgHttpConnection {
             self.url = new_url;
         }
     }
+
 }
 
 impl HgWireConnection for HgHttpConnection {
@@ -426,15 +762,16 @@ impl HgWireConnection for HgHttpConnection {
      *  * zlib stream when called over HTTP. */
     fn changegroup_command(&mut self, out: &mut (dyn Write + Send), command: &str, args: HgArgs) {
         let mut http_req = self.start_command_request(command, args);
+        let engines = self.negotiate_compression();
         if let Some(media_type) = self
             .get_capability(b"httpmediatype")
             .and_then(|c| c.to_str().ok())
         {
             if media_type.split(',').any(|t| t == "0.2tx") {
-                //TODO: Allow to disable individual features via configuration.
-                //TODO: Only send compression types the server reported supporting.
-                //TODO: Tests!
-                http_req.header("X-HgProto-1", "0.1 0.2 comp=zstd,zlib,none,bzip2");
+                http_req.header(
+                    "X-HgProto-1",
+                    &format!("0.1 0.2 comp={}", engines.join(",")),
+                );
             }
         }
         let mut http_resp = http_req.execute().unwrap();
@@ -452,6 +789,14 @@ impl HgWireConnection for HgHttpConnection {
                     .take(comp_len)
                     .read_to_end(&mut comp)
                     .unwrap();
+                // The server must not pick an engine we didn't advertise;
+                // treat that as a protocol error rather than guessing.
+                if !engines.iter().any(|e| e.as_bytes() == &comp[..]) {
+                    die!(
+                        "Server responded with compression {} that we did not advertise",
+                        String::from_utf8_lossy(&comp)
+                    );
+                }
                 let mut reader: Box<dyn Read> = match &comp[..] {
                     b"zstd" => Box::new(ZstdDecoder::new(http_resp).unwrap()),
                     b"zlib" => Box::new(ZlibDecoder::new(http_resp)),
@@ -477,7 +822,16 @@ impl HgWireConnection for HgHttpConnection {
 
     fn push_command(&mut self, response: &mut strbuf, input: File, command: &str, args: HgArgs) {
         let mut http_req = self.start_command_request(command, args);
-        http_req.post_data(Box::new(input));
+        // The bundle is a seekable file, so by default send it as a single sized
+        // body: that keeps it replayable for reauth and retries in `execute` and
+        // lets the server see a Content-Length. A very large bundle can opt into
+        // chunked transfer-encoding with `cinnabar.http-push-chunked`, which
+        // avoids seeking the whole file at the cost of replayability.
+        if http_push_chunked() {
+            http_req.post_data_stream(Box::new(input));
+        } else {
+            http_req.post_data(Box::new(input));
+        }
         http_req.header("Content-Type", "application/mercurial-0.1");
         let mut http_resp = http_req.execute().unwrap();
         self.handle_redirect(&http_resp);
@@ -4,11 +4,15 @@
 
 use std::borrow::Cow;
 use std::collections::BTreeMap;
+use std::ffi::{CStr, OsStr};
 use std::io::Write;
 use std::iter::{repeat, IntoIterator};
 use std::mem;
-use std::os::raw::{c_int, c_uint, c_void};
-use std::sync::Mutex;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use bstr::{BStr, BString, ByteSlice};
 use derive_more::{Deref, Display};
@@ -17,6 +21,7 @@ use itertools::Itertools;
 use once_cell::sync::Lazy;
 use percent_encoding::{percent_decode, percent_encode, NON_ALPHANUMERIC};
 
+use crate::get_config;
 use crate::hg_data::{GitAuthorship, HgAuthorship, HgCommitter};
 use crate::libc::FdFile;
 use crate::libcinnabar::{generate_manifest, git2hg, hg2git, hg_object_id, send_buffer_to};
@@ -27,7 +32,11 @@ use crate::libgit::{
 use crate::oid::{GitObjectId, HgObjectId, ObjectId};
 use crate::oid_type;
 use crate::util::{FromBytes, ImmutBString, SliceExt, ToBoxed};
-use crate::xdiff::{apply, textdiff, PatchInfo};
+use crate::xdiff::{apply, byte_diff, textdiff, PatchInfo};
+
+mod revlog;
+
+pub use revlog::LocalRevlogStore;
 
 pub const REFS_PREFIX: &str = "refs/cinnabar/";
 pub const REPLACE_REFS_PREFIX: &str = "refs/cinnabar/replace/";
@@ -37,7 +46,7 @@ pub const BROKEN_REF: &str = "refs/cinnabar/broken";
 pub const NOTES_REF: &str = "refs/notes/cinnabar";
 
 macro_rules! hg2git {
-    ($h:ident => $g:ident($i:ident)) => {
+    ($h:ident => $g:ident($i:ident), $kind:expr) => {
         oid_type!($g($i));
         oid_type!($h(HgObjectId));
 
@@ -49,26 +58,173 @@ macro_rules! hg2git {
                         .map(|o| $g::from_unchecked($i::from_unchecked(o)))
                 }
             }
+
+            /// Resolve a (possibly abbreviated) lowercase hex prefix to a full
+            /// id, using the set of known Mercurial nodes of this kind in the
+            /// `hg2git` notes tree. Returns `Ok(None)` when nothing matches and
+            /// `Err(AmbiguousPrefix)` when more than one node shares the prefix.
+            pub fn from_prefix(prefix: &[u8]) -> Result<Option<Self>, AmbiguousPrefix> {
+                Ok(hg_node_index($kind)
+                    .resolve(prefix)?
+                    .and_then(|hex| Self::from_bytes(&hex).ok()))
+            }
+
+            /// Length of the shortest hex prefix that unambiguously abbreviates
+            /// this id among all known Mercurial nodes of this kind, clamped to
+            /// the full 40 hex digits.
+            pub fn shortest_unique_prefix_len(&self) -> usize {
+                hg_node_index($kind).shortest_unique_prefix_len(&hg_node_hex(self))
+            }
         }
     };
 }
 
-hg2git!(HgChangesetId => GitChangesetId(CommitId));
-hg2git!(HgManifestId => GitManifestId(CommitId));
-hg2git!(HgFileId => GitFileId(BlobId));
+hg2git!(HgChangesetId => GitChangesetId(CommitId), HgObjectKind::Changeset);
+hg2git!(HgManifestId => GitManifestId(CommitId), HgObjectKind::Manifest);
+hg2git!(HgFileId => GitFileId(BlobId), HgObjectKind::File);
+
+/// The kind of Mercurial object an `hg2git` entry maps to. The notes tree mixes
+/// all three, so the prefix index is split by kind to keep, say, a manifest
+/// node from answering a changeset abbreviation.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HgObjectKind {
+    Changeset,
+    Manifest,
+    File,
+}
+
+/// Error returned when a node prefix matches more than one known Mercurial id.
+#[derive(Debug)]
+pub struct AmbiguousPrefix;
+
+/// Render a Mercurial id as its 40-character lowercase hex form.
+fn hg_node_hex<T: std::fmt::Display>(id: &T) -> [u8; 40] {
+    let mut hex = [0u8; 40];
+    let s = id.to_string();
+    hex.copy_from_slice(s.as_bytes());
+    hex
+}
+
+/// Index of known Mercurial node ids kept in sorted hex order. A prefix is
+/// resolved by locating the lower bound and confirming exactly one entry shares
+/// it; an id is abbreviated by taking the longest common prefix with each of
+/// its immediate sorted neighbors and returning that maximum plus one. This
+/// follows the scheme jj uses in its commit index.
+struct HgNodeIndex {
+    sorted: Vec<[u8; 40]>,
+}
+
+impl HgNodeIndex {
+    fn from_hex_nodes(nodes: impl Iterator<Item = [u8; 40]>) -> Self {
+        let mut sorted: Vec<[u8; 40]> = nodes.collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+        HgNodeIndex { sorted }
+    }
+
+    fn resolve(&self, prefix: &[u8]) -> Result<Option<[u8; 40]>, AmbiguousPrefix> {
+        let lower = self.sorted.partition_point(|n| &n[..] < prefix);
+        let mut matches = self.sorted[lower..]
+            .iter()
+            .take_while(|n| n.starts_with(prefix));
+        match matches.next() {
+            None => Ok(None),
+            Some(first) if matches.next().is_none() => Ok(Some(*first)),
+            _ => Err(AmbiguousPrefix),
+        }
+    }
+
+    fn shortest_unique_prefix_len(&self, node: &[u8; 40]) -> usize {
+        let pos = self.sorted.partition_point(|n| n < node);
+        let mut len = 1;
+        if pos > 0 {
+            len = len.max(common_prefix_len(&self.sorted[pos - 1], node) + 1);
+        }
+        if let Some(next) = self.sorted[pos..].iter().find(|n| *n != node) {
+            len = len.max(common_prefix_len(next, node) + 1);
+        }
+        len.min(40)
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// The per-kind prefix indices, each wrapped in an `Arc` so callers can read
+/// one without holding the lock for the duration of a resolve/abbreviate.
+#[derive(Clone)]
+struct HgNodeIndices {
+    changeset: Arc<HgNodeIndex>,
+    manifest: Arc<HgNodeIndex>,
+    file: Arc<HgNodeIndex>,
+}
+
+static HG_NODE_INDEX: Lazy<Mutex<Option<HgNodeIndices>>> = Lazy::new(|| Mutex::new(None));
+
+/// Build (and cache) the sorted index of Mercurial nodes of a given kind from
+/// the `hg2git` notes tree. The cache is dropped by `reset_changeset_heads`,
+/// which is the point at which the metadata the index reflects can have changed.
+fn hg_node_index(kind: HgObjectKind) -> Arc<HgNodeIndex> {
+    let mut cache = HG_NODE_INDEX.lock().unwrap();
+    if cache.is_none() {
+        *cache = Some(build_hg_node_indices());
+    }
+    let indices = cache.as_ref().unwrap();
+    match kind {
+        HgObjectKind::Changeset => indices.changeset.clone(),
+        HgObjectKind::Manifest => indices.manifest.clone(),
+        HgObjectKind::File => indices.file.clone(),
+    }
+}
+
+/// Partition the `hg2git` notes into the three per-kind indices in a single
+/// pass, classifying each entry by the git object it points at.
+fn build_hg_node_indices() -> HgNodeIndices {
+    let mut changeset = Vec::new();
+    let mut manifest = Vec::new();
+    let mut file = Vec::new();
+    for (node, git_oid) in iter_hg2git_nodes() {
+        let bucket = match classify_hg_node(git_oid) {
+            HgObjectKind::Changeset => &mut changeset,
+            HgObjectKind::Manifest => &mut manifest,
+            HgObjectKind::File => &mut file,
+        };
+        bucket.push(hg_node_hex(&node));
+    }
+    HgNodeIndices {
+        changeset: Arc::new(HgNodeIndex::from_hex_nodes(changeset.into_iter())),
+        manifest: Arc::new(HgNodeIndex::from_hex_nodes(manifest.into_iter())),
+        file: Arc::new(HgNodeIndex::from_hex_nodes(file.into_iter())),
+    }
+}
+
+/// Classify an `hg2git` entry by the git object it points at: a blob is a file,
+/// a commit carrying a `git2hg` note is a changeset, and any other commit is a
+/// manifest.
+fn classify_hg_node(git_oid: GitObjectId) -> HgObjectKind {
+    let cid = GitChangesetId::from_unchecked(CommitId::from_unchecked(git_oid));
+    if unsafe { git2hg.get_note(&cid).is_some() } {
+        HgObjectKind::Changeset
+    } else if RawCommit::read(&cid).is_some() {
+        HgObjectKind::Manifest
+    } else {
+        HgObjectKind::File
+    }
+}
+
+/// Enumerate the Mercurial node ids that have a `hg2git` note, paired with the
+/// git object each maps to.
+fn iter_hg2git_nodes() -> impl Iterator<Item = (HgObjectId, GitObjectId)> {
+    unsafe { hg2git.iter() }
+}
 
 oid_type!(GitChangesetMetadataId(BlobId));
 oid_type!(GitFileMetadataId(BlobId));
 
 impl GitChangesetId {
     pub fn to_hg(&self) -> Option<HgChangesetId> {
-        //TODO: avoid repeatedly reading metadata for a given changeset.
-        //The equivalent python code was keeping a LRU cache.
-        let metadata = RawGitChangesetMetadata::read(self);
-        metadata
-            .as_ref()
-            .and_then(RawGitChangesetMetadata::parse)
-            .map(|m| m.changeset_id().clone())
+        changeset_metadata(self).map(|m| m.changeset_id.clone())
     }
 }
 
@@ -114,6 +270,171 @@ impl RawGitChangesetMetadata {
     }
 }
 
+impl GeneratedGitChangesetMetadata {
+    /// Take ownership of a borrowed parse so the full record can live in the
+    /// cache past the note blob it was parsed from. Keeping every field (not
+    /// just `changeset_id`/`extra`) means `RawHgChangeset::read` and friends can
+    /// rebuild a changeset straight from a cache hit.
+    fn from_parsed(parsed: &ParsedGitChangesetMetadata) -> Self {
+        GitChangesetMetadata {
+            changeset_id: parsed.changeset_id.clone(),
+            manifest_id: parsed.manifest_id.clone(),
+            author: parsed.author.map(ToBoxed::to_boxed),
+            extra: parsed.extra.map(ToBoxed::to_boxed),
+            files: parsed.files.map(ToBoxed::to_boxed),
+            patch: parsed.patch.map(ToBoxed::to_boxed),
+        }
+    }
+}
+
+/// A bounded cache. Eviction is by least-recently-used, as
+/// in the `moka`-style caches rgit keeps in its `Git` struct, but kept minimal:
+/// a monotonic clock stamps each access and the lowest-stamped entry is dropped
+/// once the capacity is exceeded.
+struct MetadataCache {
+    capacity: usize,
+    clock: u64,
+    entries: BTreeMap<GitChangesetId, (u64, Arc<GeneratedGitChangesetMetadata>)>,
+}
+
+impl MetadataCache {
+    fn new() -> Self {
+        MetadataCache {
+            capacity: changeset_metadata_cache_size(),
+            clock: 0,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &GitChangesetId) -> Option<Arc<GeneratedGitChangesetMetadata>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|entry| {
+            entry.0 = clock;
+            entry.1.clone()
+        })
+    }
+
+    fn insert(&mut self, key: GitChangesetId, value: Arc<GeneratedGitChangesetMetadata>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        self.entries.insert(key, (self.clock, value));
+        while self.entries.len() > self.capacity {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (stamp, _))| *stamp)
+                .map(|(k, _)| k.clone())
+            {
+                self.entries.remove(&oldest);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Capacity of the changeset metadata cache, from
+/// `cinnabar.metadata-cache-size`. Defaults to 1024 entries.
+fn changeset_metadata_cache_size() -> usize {
+    get_config("metadata-cache-size")
+        .and_then(|v| v.to_str().ok().and_then(|s| s.trim().parse().ok()))
+        .unwrap_or(1024)
+}
+
+static CHANGESET_METADATA_CACHE: Lazy<Mutex<MetadataCache>> =
+    Lazy::new(|| Mutex::new(MetadataCache::new()));
+
+/// Look up the parsed metadata for a changeset, consulting the in-memory cache
+/// first and falling back to reading and parsing the note blob on a miss.
+pub fn changeset_metadata(changeset_id: &GitChangesetId) -> Option<Arc<GeneratedGitChangesetMetadata>> {
+    {
+        let mut cache = CHANGESET_METADATA_CACHE.lock().unwrap();
+        if let Some(cached) = cache.get(changeset_id) {
+            return Some(cached);
+        }
+    }
+    let raw = RawGitChangesetMetadata::read(changeset_id)?;
+    let cached = Arc::new(GeneratedGitChangesetMetadata::from_parsed(&raw.parse()?));
+    CHANGESET_METADATA_CACHE
+        .lock()
+        .unwrap()
+        .insert(changeset_id.clone(), cached.clone());
+    Some(cached)
+}
+
+/// Reconstruct a changeset straight from a local Mercurial clone's store,
+/// bypassing a running `hg`, together with the manifest it points at and every
+/// file revision that manifest lists. `hg_dir` is the clone's `.hg` directory;
+/// the revlogs live in `hg_dir/store`. This is what the local-clone import
+/// needs: the on-disk repository is available, so the changelog, manifest, and
+/// filelogs are read directly rather than over the wire protocol. Only the
+/// changeset is returned: the manifest and file revision texts are read (an I/O
+/// error on them fails the call) and then dropped.
+fn import_from_local_store(
+    hg_dir: &Path,
+    node: &HgChangesetId,
+) -> std::io::Result<Option<RawHgChangeset>> {
+    let store = LocalRevlogStore::new(&hg_dir.join("store"));
+    let changeset = match store.changeset(&hg_node(node))? {
+        Some(changeset) => changeset,
+        None => return Ok(None),
+    };
+    if let Some(parsed) = changeset.parse() {
+        if let Some(manifest) = store.manifest(&hg_node(parsed.manifest()))? {
+            for line in manifest.lines() {
+                let Some([path, rest]) = line.splitn_exact(b'\0') else {
+                    continue;
+                };
+                // Each manifest line is `path\0<40-hex node><flags>`.
+                if let Some(node) = rest.get(..40).and_then(|h| HgFileId::from_bytes(h).ok()) {
+                    store.file(path, &hg_node(&node))?;
+                }
+            }
+        }
+    }
+    Ok(Some(changeset))
+}
+
+/// The 20-byte node id behind an `Hg*Id`.
+fn hg_node(id: &impl ObjectId) -> [u8; 20] {
+    id.as_raw_bytes()
+        .try_into()
+        .expect("hg node id is 20 bytes")
+}
+
+/// Reconstruct a changeset from a local clone's store and stream its raw text to
+/// `output`. The local-clone import drives this per changeset it discovers,
+/// instead of fetching the data over the wire. Returns `0` on success, `1` if
+/// the changeset is absent or its store cannot be read.
+#[no_mangle]
+pub unsafe extern "C" fn changeset_from_local_store(
+    hg_dir: *const c_char,
+    cs: *const hg_object_id,
+    output: c_int,
+) -> c_int {
+    let hg_dir = Path::new(OsStr::from_bytes(CStr::from_ptr(hg_dir).to_bytes()));
+    let cs = HgChangesetId::from_unchecked(HgObjectId::from(cs.as_ref().unwrap().clone()));
+    match import_from_local_store(hg_dir, &cs) {
+        Ok(Some(changeset)) => {
+            let mut output = FdFile::from_raw_fd(output);
+            send_buffer_to(&*changeset, &mut output);
+            0
+        }
+        Ok(None) => 1,
+        Err(err) => {
+            eprintln!("warning: cannot read changeset {} from local store: {}", cs, err);
+            1
+        }
+    }
+}
+
 #[derive(CopyGetters, Getters)]
 pub struct GitChangesetMetadata<B: AsRef<[u8]>> {
     #[getset(get = "pub")]
@@ -185,16 +506,40 @@ impl GeneratedGitChangesetMetadata {
         };
         let new = RawHgChangeset::from_metadata(commit, &temp)?;
         if **raw_changeset != *new {
-            // TODO: produce a better patch (byte_diff)
-            temp.patch = Some(GitChangesetPatch::from_patch_info(textdiff(
-                raw_changeset,
-                &new,
-            )));
+            let old: &[u8] = raw_changeset;
+            let new_bytes: &[u8] = &new;
+            // Prefer the byte-granular diff, which collapses a single-region
+            // edit to one span, and only fall back to the line diff when it
+            // would actually encode smaller.
+            let byte = GitChangesetPatch::from_patch_info(byte_diff(old, new_bytes).into_iter());
+            let line = GitChangesetPatch::from_patch_info(textdiff(raw_changeset, &new));
+            temp.patch = Some(if byte.len() <= line.len() { byte } else { line });
         }
         Some(temp)
     }
 }
 
+#[test]
+fn test_hg_node_index() {
+    fn hex(s: &str) -> [u8; 40] {
+        let mut a = [b'0'; 40];
+        a[..s.len()].copy_from_slice(s.as_bytes());
+        a
+    }
+    let index = HgNodeIndex::from_hex_nodes(["1111", "1234", "12ab", "abcd"].iter().map(|s| hex(s)));
+
+    // A unique prefix resolves to its full id.
+    assert_eq!(index.resolve(b"1111").unwrap(), Some(hex("1111")));
+    // A prefix shared by several ids is ambiguous.
+    assert!(index.resolve(b"12").is_err());
+    // An unknown prefix resolves to nothing.
+    assert_eq!(index.resolve(b"ffff").unwrap(), None);
+    // "1234" collides with "12ab" up to two digits, so three are needed.
+    assert_eq!(index.shortest_unique_prefix_len(&hex("1234")), 3);
+    // "abcd" shares nothing with its neighbor, so a single digit suffices.
+    assert_eq!(index.shortest_unique_prefix_len(&hex("abcd")), 1);
+}
+
 pub struct ChangesetExtra<'a> {
     data: BTreeMap<&'a BStr, &'a BStr>,
 }
@@ -313,6 +658,12 @@ impl<'a> GitChangesetPatch<'a> {
 pub struct RawHgChangeset(pub ImmutBString);
 
 impl RawHgChangeset {
+    /// Wrap the raw changeset text produced by a source other than the git
+    /// metadata (e.g. a local revlog).
+    pub fn from_raw(data: ImmutBString) -> Self {
+        Self(data)
+    }
+
     pub fn from_metadata<B: AsRef<[u8]>>(
         commit: &Commit,
         metadata: &GitChangesetMetadata<B>,
@@ -392,9 +743,8 @@ impl RawHgChangeset {
     pub fn read(oid: &GitChangesetId) -> Option<Self> {
         let commit = RawCommit::read(oid)?;
         let commit = commit.parse()?;
-        let metadata = RawGitChangesetMetadata::read(oid)?;
-        let metadata = metadata.parse()?;
-        Self::from_metadata(&commit, &metadata)
+        let metadata = changeset_metadata(oid)?;
+        Self::from_metadata(&commit, &*metadata)
     }
 
     pub fn parse(&self) -> Option<HgChangeset> {
@@ -455,6 +805,12 @@ impl<'a> HgChangeset<'a> {
 pub struct RawHgManifest(ImmutBString);
 
 impl RawHgManifest {
+    /// Wrap the raw manifest text produced by a source other than the git
+    /// metadata (e.g. a local revlog).
+    pub fn from_raw(data: ImmutBString) -> Self {
+        Self(data)
+    }
+
     pub fn read(oid: &GitManifestId) -> Option<Self> {
         unsafe {
             generate_manifest(&(&***oid).clone().into())
@@ -469,6 +825,12 @@ impl RawHgManifest {
 pub struct RawHgFile(ImmutBString);
 
 impl RawHgFile {
+    /// Wrap the raw file revision text (including any `\x01\n` metadata header)
+    /// produced by a source other than the git metadata (e.g. a local revlog).
+    pub fn from_raw(data: ImmutBString) -> Self {
+        Self(data)
+    }
+
     pub fn read(oid: &GitFileId, metadata: Option<&GitFileMetadataId>) -> Option<Self> {
         let mut result = Vec::new();
         if let Some(metadata) = metadata {
@@ -481,6 +843,59 @@ impl RawHgFile {
     }
 }
 
+/// A recoverable metadata-corruption condition: a note lookup, blob read, or
+/// parse that a healthy repository would never fail. It carries a diagnostic
+/// naming the object that failed and why, so the FFI entry points can report
+/// and skip a broken object rather than aborting the whole process.
+#[derive(Debug)]
+pub struct BrokenMetadata(String);
+
+type MetadataResult<T> = Result<T, BrokenMetadata>;
+
+macro_rules! broken {
+    ($($arg:tt)*) => {
+        BrokenMetadata(format!($($arg)*))
+    };
+}
+
+/// Sticky flag set the first time any entry point hits broken metadata, used so
+/// we only write [`BROKEN_REF`] once per run no matter how many objects are
+/// found to be corrupt.
+static METADATA_BROKEN: AtomicBool = AtomicBool::new(false);
+
+/// Report a recoverable corruption and mark the metadata broken on disk. The
+/// process keeps running so the caller can skip the offending object; the first
+/// such report points [`BROKEN_REF`] at the current metadata so a later
+/// `git cinnabar fsck`/import surfaces the corruption.
+fn report_broken_metadata(err: &BrokenMetadata) {
+    eprintln!(
+        "warning: {}; marking metadata as broken ({})",
+        err.0, BROKEN_REF
+    );
+    if !METADATA_BROKEN.swap(true, Ordering::Relaxed) {
+        mark_metadata_broken();
+    }
+}
+
+/// Point [`BROKEN_REF`] at the current metadata commit so a subsequent
+/// `git cinnabar fsck`/import reports the corruption and skips it. Best-effort:
+/// we're already on the recovery path, so a failure to move the ref is only
+/// warned about rather than propagated.
+fn mark_metadata_broken() {
+    let metadata = match get_oid_committish(METADATA_REF.as_bytes()) {
+        Some(cid) => cid,
+        None => return,
+    };
+    let updated = std::process::Command::new("git")
+        .args(["update-ref", BROKEN_REF, &metadata.to_string()])
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false);
+    if !updated {
+        eprintln!("warning: could not point {} at {}", BROKEN_REF, metadata);
+    }
+}
+
 #[derive(Debug)]
 struct ChangesetHeads {
     generation: usize,
@@ -489,29 +904,49 @@ struct ChangesetHeads {
 
 impl ChangesetHeads {
     fn new() -> Self {
-        get_oid_committish(b"refs/cinnabar/metadata^1").map_or_else(
-            || ChangesetHeads {
+        // Fall back to an empty set on corruption rather than panicking during
+        // the lazy initialization of `CHANGESET_HEADS`.
+        Self::try_new().unwrap_or_else(|err| {
+            report_broken_metadata(&err);
+            ChangesetHeads {
                 generation: 0,
                 heads: BTreeMap::new(),
-            },
-            |cid| {
-                let commit = RawCommit::read(&cid).unwrap();
-                let commit = commit.parse().unwrap();
-                let heads = commit
-                    .body()
-                    .lines()
-                    .enumerate()
-                    .map(|(n, l)| {
-                        let [h, b] = l.splitn_exact(b' ').unwrap();
-                        (HgChangesetId::from_bytes(h).unwrap(), (BString::from(b), n))
-                    })
-                    .collect::<BTreeMap<_, _>>();
-                ChangesetHeads {
-                    generation: heads.len(),
-                    heads,
-                }
-            },
-        )
+            }
+        })
+    }
+
+    fn try_new() -> MetadataResult<Self> {
+        let cid = match get_oid_committish(b"refs/cinnabar/metadata^1") {
+            Some(cid) => cid,
+            None => {
+                return Ok(ChangesetHeads {
+                    generation: 0,
+                    heads: BTreeMap::new(),
+                })
+            }
+        };
+        let commit = RawCommit::read(&cid)
+            .ok_or_else(|| broken!("cannot read changeset heads commit {}", cid))?;
+        let commit = commit
+            .parse()
+            .ok_or_else(|| broken!("cannot parse changeset heads commit {}", cid))?;
+        let heads = commit
+            .body()
+            .lines()
+            .enumerate()
+            .map(|(n, l)| {
+                let [h, b] = l
+                    .splitn_exact(b' ')
+                    .ok_or_else(|| broken!("malformed changeset head entry {:?}", l.as_bstr()))?;
+                let h = HgChangesetId::from_bytes(h)
+                    .map_err(|_| broken!("invalid changeset id in heads: {:?}", h.as_bstr()))?;
+                Ok((h, (BString::from(b), n)))
+            })
+            .collect::<MetadataResult<BTreeMap<_, _>>>()?;
+        Ok(ChangesetHeads {
+            generation: heads.len(),
+            heads,
+        })
     }
 }
 
@@ -521,46 +956,68 @@ static CHANGESET_HEADS: Lazy<Mutex<ChangesetHeads>> =
 #[no_mangle]
 pub unsafe extern "C" fn add_changeset_head(cs: *const hg_object_id, oid: *const object_id) {
     let cs = HgChangesetId::from_unchecked(HgObjectId::from(cs.as_ref().unwrap().clone()));
+    let oid = GitObjectId::from(oid.as_ref().unwrap().clone());
+    if let Err(err) = try_add_changeset_head(&cs, oid) {
+        report_broken_metadata(&err);
+    }
+}
 
+fn try_add_changeset_head(cs: &HgChangesetId, oid: GitObjectId) -> MetadataResult<()> {
     // Because we don't keep track of many of these things in the rust side right now,
     // we do extra work here. Eventually, this will be simplified.
     let mut heads = CHANGESET_HEADS.lock().unwrap();
-    let oid = GitObjectId::from(oid.as_ref().unwrap().clone());
     if oid == GitObjectId::null() {
-        heads.heads.remove(&cs);
-    } else {
-        let blob = BlobId::from_unchecked(oid);
-        let cs_meta = RawGitChangesetMetadata(RawBlob::read(&blob).unwrap());
-        let meta = cs_meta.parse().unwrap();
-        assert_eq!(meta.changeset_id, cs);
-        let branch = meta
+        heads.heads.remove(cs);
+        return Ok(());
+    }
+    let blob = BlobId::from_unchecked(oid);
+    let cs_meta = RawGitChangesetMetadata(
+        RawBlob::read(&blob).ok_or_else(|| broken!("cannot read metadata blob {}", blob))?,
+    );
+    let meta = cs_meta
+        .parse()
+        .ok_or_else(|| broken!("cannot parse metadata blob {} for changeset {}", blob, cs))?;
+    if meta.changeset_id != *cs {
+        return Err(broken!(
+            "metadata blob {} records changeset {} but was stored for {}",
+            blob,
+            meta.changeset_id,
+            cs
+        ));
+    }
+    let branch = meta
+        .extra()
+        .and_then(|e| e.get(b"branch"))
+        .unwrap_or(b"default");
+    let cid = cs
+        .to_git()
+        .ok_or_else(|| broken!("no git commit for changeset {}", cs))?;
+    let commit = RawCommit::read(&cid).ok_or_else(|| broken!("cannot read commit {}", cid))?;
+    let commit = commit
+        .parse()
+        .ok_or_else(|| broken!("cannot parse commit {}", cid))?;
+    for parent in commit.parents() {
+        let parent = lookup_replace_commit(parent);
+        let parent_id = GitChangesetId::from_unchecked(parent.into_owned());
+        let parent_meta = changeset_metadata(&parent_id)
+            .ok_or_else(|| broken!("cannot read metadata for parent {}", parent_id))?;
+        let parent_branch = parent_meta
             .extra()
             .and_then(|e| e.get(b"branch"))
             .unwrap_or(b"default");
-        let cid = cs.to_git().unwrap();
-        let commit = RawCommit::read(&cid).unwrap();
-        let commit = commit.parse().unwrap();
-        for parent in commit.parents() {
-            let parent = lookup_replace_commit(parent);
-            let parent_cs_meta =
-                RawGitChangesetMetadata::read(&GitChangesetId::from_unchecked(parent.into_owned()))
-                    .unwrap();
-            let parent_meta = parent_cs_meta.parse().unwrap();
-            let parent_branch = parent_meta
-                .extra()
-                .and_then(|e| e.get(b"branch"))
-                .unwrap_or(b"default");
-            if parent_branch == branch {
-                if let Some((b, _)) = heads.heads.get(&parent_meta.changeset_id) {
-                    assert_eq!(b.as_bstr(), parent_branch.as_bstr());
-                    heads.heads.remove(&parent_meta.changeset_id);
-                }
+        if parent_branch == branch {
+            if let Some((b, _)) = heads.heads.get(parent_meta.changeset_id()) {
+                debug_assert_eq!(b.as_bstr(), parent_branch.as_bstr());
+                heads.heads.remove(parent_meta.changeset_id());
             }
         }
-        let generation = heads.generation;
-        heads.generation += 1;
-        heads.heads.insert(cs, (BString::from(branch), generation));
     }
+    let generation = heads.generation;
+    heads.generation += 1;
+    heads
+        .heads
+        .insert(cs.clone(), (BString::from(branch), generation));
+    Ok(())
 }
 
 #[no_mangle]
@@ -586,7 +1043,10 @@ extern "C" {
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn store_changesets_metadata(blob: *const object_id, result: *mut object_id) {
+pub unsafe extern "C" fn store_changesets_metadata(
+    blob: *const object_id,
+    result: *mut object_id,
+) {
     let result = result.as_mut().unwrap();
     let mut tree = vec![];
     if let Some(blob) = blob.as_ref() {
@@ -605,14 +1065,24 @@ pub unsafe extern "C" fn store_changesets_metadata(blob: *const object_id, resul
     drop(tree);
     let mut commit = vec![];
     writeln!(commit, "tree {}", GitObjectId::from(tid)).ok();
-    let heads = CHANGESET_HEADS.lock().unwrap();
-    for (_, head) in heads.heads.iter().map(|(h, (_, g))| (g, h)).sorted() {
-        writeln!(commit, "parent {}", head.to_git().unwrap()).ok();
-    }
-    writeln!(commit, "author  <cinnabar@git> 0 +0000").ok();
-    writeln!(commit, "committer  <cinnabar@git> 0 +0000").ok();
-    for (_, head, branch) in heads.heads.iter().map(|(h, (b, g))| (g, h, b)).sorted() {
-        write!(commit, "\n{} {}", head, branch).ok();
+    {
+        let heads = CHANGESET_HEADS.lock().unwrap();
+        for (_, head) in heads.heads.iter().map(|(h, (_, g))| (g, h)).sorted() {
+            match head.to_git() {
+                Some(cid) => {
+                    writeln!(commit, "parent {}", cid).ok();
+                }
+                None => {
+                    report_broken_metadata(&broken!("no git commit for changeset head {}", head));
+                    return;
+                }
+            }
+        }
+        writeln!(commit, "author  <cinnabar@git> 0 +0000").ok();
+        writeln!(commit, "committer  <cinnabar@git> 0 +0000").ok();
+        for (_, head, branch) in heads.heads.iter().map(|(h, (b, g))| (g, h, b)).sorted() {
+            write!(commit, "\n{} {}", head, branch).ok();
+        }
     }
     write_object_file_flags(
         commit.as_ptr() as *const c_void,
@@ -627,6 +1097,8 @@ pub unsafe extern "C" fn store_changesets_metadata(blob: *const object_id, resul
 pub unsafe extern "C" fn reset_changeset_heads() {
     let mut heads = CHANGESET_HEADS.lock().unwrap();
     *heads = ChangesetHeads::new();
+    CHANGESET_METADATA_CACHE.lock().unwrap().clear();
+    *HG_NODE_INDEX.lock().unwrap() = None;
 }
 
 #[no_mangle]
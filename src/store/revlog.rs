@@ -0,0 +1,326 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Minimal reader for Mercurial revlogs straight off a local `.hg/store`, so an
+//! import from an on-disk clone doesn't have to talk to `hg`. The format and
+//! the delta-chain reconstruction mirror what the `hg-parser` crate does.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use flate2::read::ZlibDecoder;
+
+use crate::store::{RawHgChangeset, RawHgFile, RawHgManifest};
+use crate::util::ImmutBString;
+
+/// `flags` bit (stored in the high half of the version word) signalling that a
+/// revlog keeps its data inline in the `.i` file rather than in a sibling
+/// `.d`.
+const FLAG_INLINE_DATA: u32 = 1 << 16;
+
+/// `flags` bit signalling a generaldelta revlog, where each record's `base`
+/// field names the arbitrary revision its delta is against rather than just the
+/// snapshot at the head of a contiguous delta run.
+const FLAG_GENERALDELTA: u32 = 1 << 17;
+
+/// Size, in bytes, of a single version-1 (`ng`) index record.
+const INDEX_ENTRY_SIZE: usize = 64;
+
+/// One parsed index record. Revision numbers are indices into the revlog; a
+/// missing parent is stored as `-1`.
+#[derive(Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    compressed_len: usize,
+    uncompressed_len: usize,
+    base: i32,
+    #[allow(dead_code)]
+    link: i32,
+    #[allow(dead_code)]
+    parents: [i32; 2],
+    node: [u8; 20],
+}
+
+/// A single revlog (one changelog, manifest, or filelog).
+pub struct Revlog {
+    index: Vec<IndexEntry>,
+    by_node: HashMap<[u8; 20], usize>,
+    inline: bool,
+    generaldelta: bool,
+    /// Raw bytes of the `.i` file, used directly for inline data.
+    index_data: Vec<u8>,
+    /// Raw bytes of the `.d` file, for non-inline revlogs.
+    revision_data: Vec<u8>,
+}
+
+impl Revlog {
+    /// Open the revlog whose index lives at `index_path` (a `*.i` file).
+    pub fn open(index_path: &Path) -> io::Result<Self> {
+        let index_data = read_file(index_path)?;
+        let flags = if index_data.is_empty() {
+            0
+        } else {
+            read_u32(&index_data, 0)
+        };
+        let inline = (flags & FLAG_INLINE_DATA) == FLAG_INLINE_DATA;
+        let generaldelta = (flags & FLAG_GENERALDELTA) == FLAG_GENERALDELTA;
+
+        let mut index = Vec::new();
+        let mut by_node = HashMap::new();
+        // For an inline revlog, records and their data chunks are interleaved,
+        // so the stored offset is not the position in this file; we track the
+        // running read position ourselves.
+        let mut pos = 0usize;
+        while pos + INDEX_ENTRY_SIZE <= index_data.len() {
+            let entry = parse_entry(&index_data[pos..pos + INDEX_ENTRY_SIZE], index.len() == 0);
+            by_node.insert(entry.node, index.len());
+            pos += INDEX_ENTRY_SIZE;
+            if inline {
+                pos += entry.compressed_len;
+            }
+            index.push(entry);
+            if inline {
+                // The last record of an inline revlog may be followed by its
+                // data chunk but no further record; stop once the remainder is
+                // too short for another header.
+                if pos >= index_data.len() {
+                    break;
+                }
+            }
+        }
+
+        let revision_data = if inline {
+            Vec::new()
+        } else {
+            read_file(&index_path.with_extension("d")).unwrap_or_default()
+        };
+
+        Ok(Revlog {
+            index,
+            by_node,
+            inline,
+            generaldelta,
+            index_data,
+            revision_data,
+        })
+    }
+
+    /// The raw bytes of the chunk backing revision `rev`, before decompression.
+    fn raw_chunk(&self, rev: usize) -> &[u8] {
+        let entry = &self.index[rev];
+        if self.inline {
+            // The chunk sits right after this record's header; the stored
+            // offset doesn't account for the interleaved headers, so we locate
+            // the chunk from the record's position instead.
+            let start = (rev + 1) * INDEX_ENTRY_SIZE
+                + (0..rev).map(|r| self.index[r].compressed_len).sum::<usize>();
+            &self.index_data[start..start + entry.compressed_len]
+        } else {
+            let start = entry.offset as usize;
+            &self.revision_data[start..start + entry.compressed_len]
+        }
+    }
+
+    /// Fully reconstruct the text of revision `rev` by reading the snapshot at
+    /// the head of its delta chain and applying the intervening deltas forward.
+    /// The way the chain is assembled depends on the revlog's delta scheme.
+    fn revision(&self, rev: usize) -> io::Result<Vec<u8>> {
+        let base = self.index[rev].base as usize;
+        let chain: Vec<usize> = if self.generaldelta {
+            // Each record's `base` names the revision its delta is against;
+            // follow that back to the snapshot (where `base == rev`).
+            let mut chain = vec![rev];
+            let mut cur = rev;
+            while self.index[cur].base as usize != cur {
+                cur = self.index[cur].base as usize;
+                chain.push(cur);
+            }
+            chain.reverse();
+            chain
+        } else {
+            // Classic revlogs store a snapshot at `base` followed by a
+            // contiguous run of deltas; each intermediate revision has to be
+            // applied in turn.
+            (base..=rev).collect()
+        };
+
+        let mut text = decompress(self.raw_chunk(chain[0]))?;
+        for &delta_rev in &chain[1..] {
+            let delta = decompress(self.raw_chunk(delta_rev))?;
+            text = apply_delta(&text, &delta)?;
+        }
+        debug_assert_eq!(text.len(), self.index[rev].uncompressed_len);
+        Ok(text)
+    }
+
+    /// Reconstruct the revision identified by its 20-byte node id.
+    pub fn revision_by_node(&self, node: &[u8; 20]) -> io::Result<Option<Vec<u8>>> {
+        match self.by_node.get(node) {
+            Some(&rev) => self.revision(rev).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A `.hg/store` opened for reading revisions directly from its revlogs.
+pub struct LocalRevlogStore {
+    store: PathBuf,
+}
+
+impl LocalRevlogStore {
+    /// Open the store rooted at `store_path` (the `.hg/store` directory).
+    pub fn new(store_path: &Path) -> Self {
+        LocalRevlogStore {
+            store: store_path.to_path_buf(),
+        }
+    }
+
+    fn open(&self, relative: &Path) -> io::Result<Revlog> {
+        Revlog::open(&self.store.join(relative))
+    }
+
+    /// Read a changeset by node from `00changelog.i`.
+    pub fn changeset(&self, node: &[u8; 20]) -> io::Result<Option<RawHgChangeset>> {
+        Ok(self
+            .open(Path::new("00changelog.i"))?
+            .revision_by_node(node)?
+            .map(|text| RawHgChangeset::from_raw(into_boxed(text))))
+    }
+
+    /// Read a manifest by node from `00manifest.i`.
+    pub fn manifest(&self, node: &[u8; 20]) -> io::Result<Option<RawHgManifest>> {
+        Ok(self
+            .open(Path::new("00manifest.i"))?
+            .revision_by_node(node)?
+            .map(|text| RawHgManifest::from_raw(into_boxed(text))))
+    }
+
+    /// Read a file revision by node from the filelog of `path`.
+    pub fn file(&self, path: &[u8], node: &[u8; 20]) -> io::Result<Option<RawHgFile>> {
+        Ok(self
+            .open(&store_path_for(path))?
+            .revision_by_node(node)?
+            .map(|text| RawHgFile::from_raw(into_boxed(text))))
+    }
+}
+
+/// Map a repository path to its filelog index under `data/`. Mercurial's store
+/// encoding is more involved than this; only the `data/<path>.i` layout is
+/// handled here, which covers the common untangled store.
+fn store_path_for(path: &[u8]) -> PathBuf {
+    let mut p = PathBuf::from("data");
+    p.push(String::from_utf8_lossy(path).into_owned());
+    p.set_extension(
+        p.extension()
+            .map(|e| format!("{}.i", e.to_string_lossy()))
+            .unwrap_or_else(|| "i".to_string()),
+    );
+    p
+}
+
+fn parse_entry(record: &[u8], is_first: bool) -> IndexEntry {
+    let mut reader = record;
+    let offset_flags = reader.read_u64::<BigEndian>().unwrap();
+    let compressed_len = reader.read_i32::<BigEndian>().unwrap() as usize;
+    let uncompressed_len = reader.read_i32::<BigEndian>().unwrap() as usize;
+    let base = reader.read_i32::<BigEndian>().unwrap();
+    let link = reader.read_i32::<BigEndian>().unwrap();
+    let p1 = reader.read_i32::<BigEndian>().unwrap();
+    let p2 = reader.read_i32::<BigEndian>().unwrap();
+    let mut node = [0u8; 20];
+    node.copy_from_slice(&reader[..20]);
+    // The first record packs the version word in the high bytes of the offset
+    // field; its real offset is zero.
+    let offset = if is_first { 0 } else { offset_flags >> 16 };
+    IndexEntry {
+        offset,
+        compressed_len,
+        uncompressed_len,
+        base,
+        link,
+        parents: [p1, p2],
+        node,
+    }
+}
+
+/// Decompress a revlog chunk according to its leading byte: `x` is a zlib
+/// stream, `u` is raw data after the marker, and `\0` is raw data that includes
+/// the leading byte (the form Mercurial uses when the payload would otherwise
+/// look like a compression marker).
+fn decompress(chunk: &[u8]) -> io::Result<Vec<u8>> {
+    match chunk.first() {
+        None => Ok(Vec::new()),
+        Some(b'x') => {
+            let mut out = Vec::new();
+            ZlibDecoder::new(chunk).read_to_end(&mut out)?;
+            Ok(out)
+        }
+        Some(b'u') => Ok(chunk[1..].to_vec()),
+        Some(0) => Ok(chunk.to_vec()),
+        Some(_) => Ok(chunk.to_vec()),
+    }
+}
+
+/// Apply a Mercurial binary delta to `base`. A delta is a sequence of
+/// `(start, end, replacement)` operations, each replacing `base[start..end]`
+/// with the following `len` bytes; the operations are ordered and
+/// non-overlapping.
+fn apply_delta(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(base.len());
+    let mut reader = delta;
+    let mut last = 0usize;
+    while !reader.is_empty() {
+        let start = reader.read_u32::<BigEndian>()? as usize;
+        let end = reader.read_u32::<BigEndian>()? as usize;
+        let len = reader.read_u32::<BigEndian>()? as usize;
+        if start > base.len() || end > base.len() || start < last || len > reader.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed revlog delta",
+            ));
+        }
+        out.extend_from_slice(&base[last..start]);
+        out.extend_from_slice(&reader[..len]);
+        reader = &reader[len..];
+        last = end;
+    }
+    out.extend_from_slice(&base[last..]);
+    Ok(out)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    File::open(path)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+fn into_boxed(data: Vec<u8>) -> ImmutBString {
+    data.into_boxed_slice()
+}
+
+#[test]
+fn test_apply_delta() {
+    // Replace "quick" with "slow" in a single op.
+    let base = b"the quick brown fox";
+    let mut delta = Vec::new();
+    delta.extend_from_slice(&4u32.to_be_bytes());
+    delta.extend_from_slice(&9u32.to_be_bytes());
+    delta.extend_from_slice(&4u32.to_be_bytes());
+    delta.extend_from_slice(b"slow");
+    assert_eq!(apply_delta(base, &delta).unwrap(), b"the slow brown fox");
+}
+
+#[test]
+fn test_decompress() {
+    assert_eq!(decompress(b"uhello").unwrap(), b"hello");
+    assert_eq!(decompress(&[0, b'h', b'i']).unwrap(), &[0, b'h', b'i']);
+    assert!(decompress(b"").unwrap().is_empty());
+}